@@ -5,30 +5,66 @@
 use compositing::CompositorChan;
 use layout::layout_task::LayoutTask;
 
+use geom::rect::Rect;
 use geom::size::Size2D;
 use gfx::render_task::{PaintPermissionGranted, PaintPermissionRevoked};
 use gfx::render_task::{RenderChan, RenderTask};
+use script::layout_interface;
 use script::layout_interface::LayoutChan;
 use script::script_task::LoadMsg;
 use script::script_task::{AttachLayoutMsg, NewLayoutInfo, ScriptTask, ScriptChan};
 use script::script_task;
+use servo_msg::compositor_msg::LayerId;
 use servo_msg::constellation_msg::{ConstellationChan, Failure, PipelineId, SubpageId};
 use servo_net::image_cache_task::ImageCacheTask;
 use servo_net::resource_task::ResourceTask;
 use servo_util::opts::Opts;
 use servo_util::time::ProfilerChan;
+use std::comm::Select;
+use std::io::timer::Timer;
 use std::rc::Rc;
 use url::Url;
 
+/// Fallback timeout used if a pipeline's `Opts` somehow specify zero; see
+/// `Opts::pipeline_shutdown_timeout_ms`.
+static DEFAULT_SHUTDOWN_TIMEOUT_MS: u64 = 5000;
+
+/// Messages sent to a layout task over its `LayoutControlChan`, a channel kept separate from the
+/// ordinary `LayoutChan` so that they are delivered even when layout is backed up processing
+/// other work.
+pub enum LayoutControlMsg {
+    /// Tells layout which parts of each of its layers are actually visible on screen, so that it
+    /// can skip or throttle work for content that is scrolled out of view.
+    SetVisibleRects(Vec<(LayerId, Rect<f32>)>),
+}
+
+/// A channel to send `LayoutControlMsg`s to a layout task, bypassing its main message queue.
+#[deriving(Clone)]
+pub struct LayoutControlChan(pub Sender<LayoutControlMsg>);
+
+impl LayoutControlChan {
+    /// Creates a new layout control channel and its associated port.
+    pub fn new() -> (Receiver<LayoutControlMsg>, LayoutControlChan) {
+        let (chan, port) = channel();
+        (port, LayoutControlChan(chan))
+    }
+}
+
 /// A uniquely-identifiable pipeline of script task, layout task, and render task.
 pub struct Pipeline {
     pub id: PipelineId,
     pub subpage_id: Option<SubpageId>,
     pub script_chan: ScriptChan,
     pub layout_chan: LayoutChan,
+    /// A channel to send messages to layout that bypass the main layout message queue, so that
+    /// they are delivered even when layout is backed up processing other work.
+    pub layout_control_chan: LayoutControlChan,
     pub render_chan: RenderChan,
     pub layout_shutdown_port: Receiver<()>,
     pub render_shutdown_port: Receiver<()>,
+    /// How long to wait for this pipeline's layout and render tasks to acknowledge shutdown
+    /// before giving up on them, sourced from `Opts::pipeline_shutdown_timeout_ms`.
+    pub shutdown_timeout_ms: u64,
     /// The most recently loaded url
     pub url: Url,
 }
@@ -39,12 +75,59 @@ pub struct CompositionPipeline {
     pub id: PipelineId,
     pub script_chan: ScriptChan,
     pub render_chan: RenderChan,
+    pub layout_control_chan: LayoutControlChan,
+}
+
+/// Allows the layout task implementation used to drive a pipeline to be swapped out, e.g. for a
+/// mock or alternate layout engine in headless testing or benchmarking.
+pub trait LayoutTaskFactory {
+    /// Creates a layout task for the pipeline and sends it on its way.
+    fn create(id: PipelineId,
+              port: Receiver<layout_interface::Msg>,
+              chan: LayoutChan,
+              control_chan: LayoutControlChan,
+              constellation_chan: ConstellationChan,
+              failure_msg: Failure,
+              script_chan: ScriptChan,
+              render_chan: RenderChan,
+              img_cache_task: ImageCacheTask,
+              opts: Opts,
+              profiler_chan: ProfilerChan,
+              shutdown_chan: Sender<()>);
+}
+
+impl LayoutTaskFactory for LayoutTask {
+    fn create(id: PipelineId,
+              port: Receiver<layout_interface::Msg>,
+              chan: LayoutChan,
+              control_chan: LayoutControlChan,
+              constellation_chan: ConstellationChan,
+              failure_msg: Failure,
+              script_chan: ScriptChan,
+              render_chan: RenderChan,
+              img_cache_task: ImageCacheTask,
+              opts: Opts,
+              profiler_chan: ProfilerChan,
+              shutdown_chan: Sender<()>) {
+        LayoutTask::create(id,
+                           port,
+                           chan,
+                           control_chan,
+                           constellation_chan,
+                           failure_msg,
+                           script_chan,
+                           render_chan,
+                           img_cache_task,
+                           opts,
+                           profiler_chan,
+                           shutdown_chan);
+    }
 }
 
 impl Pipeline {
     /// Starts a render task, layout task, and script task. Returns the channels wrapped in a
     /// struct.
-    pub fn with_script(id: PipelineId,
+    pub fn with_script<LTF: LayoutTaskFactory>(id: PipelineId,
                        subpage_id: SubpageId,
                        constellation_chan: ConstellationChan,
                        compositor_chan: CompositorChan,
@@ -55,6 +138,7 @@ impl Pipeline {
                        url: Url)
                        -> Pipeline {
         let (layout_port, layout_chan) = LayoutChan::new();
+        let (layout_control_port, layout_control_chan) = LayoutControlChan::new();
         let (render_port, render_chan) = RenderChan::new();
         let (render_shutdown_chan, render_shutdown_port) = channel();
         let (layout_shutdown_chan, layout_shutdown_port) = channel();
@@ -73,17 +157,18 @@ impl Pipeline {
                            profiler_chan.clone(),
                            render_shutdown_chan);
 
-        LayoutTask::create(id,
-                           layout_port,
-                           layout_chan.clone(),
-                           constellation_chan,
-                           failure,
-                           script_pipeline.script_chan.clone(),
-                           render_chan.clone(),
-                           image_cache_task.clone(),
-                           opts.clone(),
-                           profiler_chan,
-                           layout_shutdown_chan);
+        LTF::create(id,
+                   layout_port,
+                   layout_chan.clone(),
+                   layout_control_port,
+                   constellation_chan,
+                   failure,
+                   script_pipeline.script_chan.clone(),
+                   render_chan.clone(),
+                   image_cache_task.clone(),
+                   opts.clone(),
+                   profiler_chan,
+                   layout_shutdown_chan);
 
         let new_layout_info = NewLayoutInfo {
             old_pipeline_id: script_pipeline.id.clone(),
@@ -99,13 +184,15 @@ impl Pipeline {
                       Some(subpage_id),
                       script_pipeline.script_chan.clone(),
                       layout_chan,
+                      layout_control_chan,
                       render_chan,
                       layout_shutdown_port,
                       render_shutdown_port,
+                      opts.pipeline_shutdown_timeout_ms,
                       url)
     }
 
-    pub fn create(id: PipelineId,
+    pub fn create<LTF: LayoutTaskFactory>(id: PipelineId,
                   subpage_id: Option<SubpageId>,
                   constellation_chan: ConstellationChan,
                   compositor_chan: CompositorChan,
@@ -118,6 +205,7 @@ impl Pipeline {
                   -> Pipeline {
         let (script_port, script_chan) = ScriptChan::new();
         let (layout_port, layout_chan) = LayoutChan::new();
+        let (layout_control_port, layout_control_chan) = LayoutControlChan::new();
         let (render_port, render_chan) = RenderChan::new();
         let (render_shutdown_chan, render_shutdown_port) = channel();
         let (layout_shutdown_chan, layout_shutdown_port) = channel();
@@ -125,9 +213,11 @@ impl Pipeline {
                                      subpage_id,
                                      script_chan.clone(),
                                      layout_chan.clone(),
+                                     layout_control_chan.clone(),
                                      render_chan.clone(),
                                      layout_shutdown_port,
                                      render_shutdown_port,
+                                     opts.pipeline_shutdown_timeout_ms,
                                      url);
 
         let failure = Failure {
@@ -155,17 +245,18 @@ impl Pipeline {
                            profiler_chan.clone(),
                            render_shutdown_chan);
 
-        LayoutTask::create(id,
-                           layout_port,
-                           layout_chan.clone(),
-                           constellation_chan,
-                           failure,
-                           script_chan.clone(),
-                           render_chan.clone(),
-                           image_cache_task,
-                           opts.clone(),
-                           profiler_chan,
-                           layout_shutdown_chan);
+        LTF::create(id,
+                   layout_port,
+                   layout_chan.clone(),
+                   layout_control_port,
+                   constellation_chan,
+                   failure,
+                   script_chan.clone(),
+                   render_chan.clone(),
+                   image_cache_task,
+                   opts.clone(),
+                   profiler_chan,
+                   layout_shutdown_chan);
 
         pipeline
     }
@@ -174,9 +265,11 @@ impl Pipeline {
                subpage_id: Option<SubpageId>,
                script_chan: ScriptChan,
                layout_chan: LayoutChan,
+               layout_control_chan: LayoutControlChan,
                render_chan: RenderChan,
                layout_shutdown_port: Receiver<()>,
                render_shutdown_port: Receiver<()>,
+               shutdown_timeout_ms: u64,
                url: Url)
                -> Pipeline {
         Pipeline {
@@ -184,9 +277,11 @@ impl Pipeline {
             subpage_id: subpage_id,
             script_chan: script_chan,
             layout_chan: layout_chan,
+            layout_control_chan: layout_control_chan,
             render_chan: render_chan,
             layout_shutdown_port: layout_shutdown_port,
             render_shutdown_port: render_shutdown_port,
+            shutdown_timeout_ms: shutdown_timeout_ms,
             url: url,
         }
     }
@@ -205,17 +300,60 @@ impl Pipeline {
         self.render_chan.chan.try_send(PaintPermissionRevoked);
     }
 
-    pub fn exit(&self) {
+    /// Tears down this pipeline's tasks. Consumes `self` so that, once a slave task is abandoned
+    /// as unresponsive, its shutdown port is force-dropped along with the rest of the pipeline
+    /// rather than lingering until some later, unrelated point where the `Pipeline` happens to be
+    /// dropped.
+    pub fn exit(self) {
         debug!("pipeline {:?} exiting", self.id);
 
         // Script task handles shutting down layout, and layout handles shutting down the renderer.
         // For now, if the script task has failed, we give up on clean shutdown.
+        let id = self.id;
+        let timeout_ms = self.shutdown_timeout_ms;
         let ScriptChan(ref chan) = self.script_chan;
-        if chan.try_send(script_task::ExitPipelineMsg(self.id)) {
-            // Wait until all slave tasks have terminated and run destructors
+        if chan.try_send(script_task::ExitPipelineMsg(id)) {
+            // Wait until all slave tasks have terminated and run destructors, but don't let a
+            // wedged layout or render task (infinite loop, deadlocked channel) hang the whole
+            // teardown: give each a bounded amount of time to acknowledge before abandoning it
+            // and letting the constellation proceed to reap the rest of the pipelines.
             // NOTE: We don't wait for script task as we don't always own it
-            self.render_shutdown_port.recv_opt();
-            self.layout_shutdown_port.recv_opt();
+            Pipeline::wait_for_shutdown(id, "render", &self.render_shutdown_port, timeout_ms);
+            Pipeline::wait_for_shutdown(id, "layout", &self.layout_shutdown_port, timeout_ms);
+        }
+
+        // `self`, including any shutdown port abandoned above, is force-dropped here.
+    }
+
+    /// Waits up to `timeout_ms` for `shutdown_port` to signal that its task has torn itself down,
+    /// logging and giving up on it rather than blocking forever if it doesn't.
+    fn wait_for_shutdown(id: PipelineId, task_name: &str, shutdown_port: &Receiver<()>, timeout_ms: u64) {
+        let timeout_ms = if timeout_ms > 0 { timeout_ms } else { DEFAULT_SHUTDOWN_TIMEOUT_MS };
+        let mut timer = match Timer::new() {
+            Ok(timer) => timer,
+            Err(e) => {
+                warn!("pipeline {:?}: could not create a shutdown timer ({}); waiting \
+                       unboundedly for the {:s} task to acknowledge shutdown",
+                      id, e, task_name);
+                shutdown_port.recv_opt();
+                return;
+            }
+        };
+        let timeout_port = timer.oneshot(timeout_ms);
+
+        let select = Select::new();
+        let mut shutdown_handle = select.handle(shutdown_port);
+        let mut timeout_handle = select.handle(&timeout_port);
+        unsafe {
+            shutdown_handle.add();
+            timeout_handle.add();
+        }
+
+        if select.wait() == timeout_handle.id() {
+            warn!("pipeline {:?} {:s} task did not acknowledge shutdown within {:?}ms; abandoning it",
+                  id, task_name, timeout_ms);
+        } else {
+            shutdown_port.recv_opt();
         }
     }
 
@@ -224,6 +362,7 @@ impl Pipeline {
             id: self.id.clone(),
             script_chan: self.script_chan.clone(),
             render_chan: self.render_chan.clone(),
+            layout_control_chan: self.layout_control_chan.clone(),
         }
     }
 }
@@ -2,24 +2,24 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use resource_task::{Done, Payload, Metadata, LoadResponse, LoaderTask, start_sending};
+use resource_task::{Done, Payload, Metadata, LoadResponse, LoaderTask, ProgressMsg, start_sending};
 
 use serialize::base64::FromBase64;
+use servo_util::opts::Opts;
+use std::task::TaskBuilder;
 use url::Url;
 
-use http::headers::test_utils::from_stream_with_str;
-use http::headers::content_type::MediaType;
+/// The size, in encoded characters, of each chunk decoded by the chunked path. Kept a multiple
+/// of 4 so that base64 decoding never has to carry a partial group across chunks.
+static DECODE_CHUNK_SIZE: uint = 32 * 1024;
 
-pub fn factory() -> LoaderTask {
+pub fn factory(opts: Opts) -> LoaderTask {
     proc(url, start_chan) {
-        // NB: we don't spawn a new task.
-        // Hypothesis: data URLs are too small for parallel base64 etc. to be worth it.
-        // Should be tested at some point.
-        load(url, start_chan)
+        load(url, start_chan, opts)
     }
 }
 
-fn load(url: Url, start_chan: Sender<LoadResponse>) {
+fn load(url: Url, start_chan: Sender<LoadResponse>, opts: Opts) {
     assert!("data" == url.scheme);
 
     let mut metadata = Metadata::default(url.clone());
@@ -31,8 +31,8 @@ fn load(url: Url, start_chan: Sender<LoadResponse>) {
         return;
     }
 
-    // ";base64" must come at the end of the content type, per RFC 2397.
-    // rust-http will fail to parse it because there's no =value part.
+    // ";base64" must come at the end of the content type, per RFC 2397. Strip it before parsing
+    // so `parse_media_type` never sees it as a bogus parameter (it has no "=value" part).
     let mut is_base64 = false;
     let mut ct_str = parts[0];
     if ct_str.ends_with(";base64") {
@@ -40,31 +40,196 @@ fn load(url: Url, start_chan: Sender<LoadResponse>) {
         ct_str = ct_str.slice_to(ct_str.as_bytes().len() - 7);
     }
 
-    // Parse the content type using rust-http.
-    // FIXME: this can go into an infinite loop! (rust-http #25)
-    let content_type: Option<MediaType> = from_stream_with_str(ct_str);
-    metadata.set_content_type(&content_type);
+    let (content_type, charset) = parse_media_type(ct_str);
+    metadata.content_type = content_type;
+    metadata.charset = charset;
 
+    let data = parts[1].to_owned();
     let progress_chan = start_sending(start_chan, metadata);
 
-    if is_base64 {
-        match parts[1].from_base64() {
-            Err(..) => {
-                progress_chan.send(Done(Err("non-base64 data uri".to_owned())));
+    if data.len() <= opts.data_uri_chunk_threshold {
+        // Small data: URIs (the common case) are cheap enough to decode and send in one go, and
+        // don't warrant spinning up a task for it.
+        decode_in_chunks(data.as_slice(), is_base64, data.len(), &progress_chan);
+    } else {
+        // Pages increasingly embed multi-megabyte base64 images and fonts as data URIs; decoding
+        // and sending the whole thing as a single giant `Payload` stalls the resource pipeline
+        // and spikes memory. Decode incrementally from a dedicated task instead, so the image
+        // cache can start consuming the first chunks before the rest has even been decoded.
+        TaskBuilder::new().named("DataLoader").spawn(proc() {
+            let progress_chan = progress_chan;
+            decode_in_chunks(data.as_slice(), is_base64, DECODE_CHUNK_SIZE, &progress_chan);
+        });
+    }
+}
+
+/// Decodes `data` in successive slices of up to `chunk_size` encoded characters, sending a
+/// `Payload` for each slice as soon as it's ready, followed by a final `Done`. Passing
+/// `chunk_size >= data.len()` decodes and sends the whole body in a single `Payload`, matching
+/// the original one-shot behaviour.
+fn decode_in_chunks(data: &str, is_base64: bool, chunk_size: uint, progress_chan: &Sender<ProgressMsg>) {
+    let bytes = data.as_bytes();
+
+    // trim_partial_unit backs off up to 2 bytes for a %XX escape, or up to 3 bytes for a
+    // multi-byte UTF-8 character, so a chunk_size below 4 could leave `start` unable to advance,
+    // spinning forever on empty Payloads. This is only a
+    // real constraint when we're actually splitting into multiple chunks; a single-shot caller
+    // (chunk_size >= data.len()) never reaches trim_partial_unit.
+    assert!(chunk_size >= bytes.len() || chunk_size >= 4,
+            "chunk_size ({}) is too small to safely split {} bytes of data", chunk_size, bytes.len());
+
+    // Never split a base64 chunk boundary in the middle of a 4-char group. Only round down when
+    // we'll actually be splitting across more than one chunk: the below-threshold, single-shot
+    // caller passes chunk_size == data.len(), and rounding that down would silently decode and
+    // send a valid-looking prefix before failing on the malformed remainder, instead of
+    // validating the whole body as one group as the doc comment above promises.
+    let chunk_size = if is_base64 && chunk_size >= 4 && chunk_size < bytes.len() {
+        chunk_size - chunk_size % 4
+    } else {
+        chunk_size
+    };
+
+    let mut start = 0u;
+    loop {
+        let mut end = (start + chunk_size).min(&bytes.len());
+        if !is_base64 {
+            end = trim_partial_unit(data, start, end);
+        }
+
+        let chunk = data.slice(start, end);
+        if is_base64 {
+            match chunk.from_base64() {
+                Err(..) => {
+                    progress_chan.send(Done(Err("non-base64 data uri".to_owned())));
+                    return;
+                }
+                Ok(decoded) => {
+                    let decoded: ~[u8] = decoded;
+                    progress_chan.send(Payload(decoded.move_iter().collect()));
+                }
             }
-            Ok(data) => {
-                let data: ~[u8] = data;
-                progress_chan.send(Payload(data.move_iter().collect()));
-                progress_chan.send(Done(Ok(())));
+        } else {
+            // Decode at the byte level rather than going through a `str`, so that charsets
+            // other than UTF-8 (e.g. `charset=latin1` or `charset=koi8-r`) survive intact:
+            // reinterpreting the percent-decoded bytes as UTF-8 would mangle any byte sequence
+            // that isn't valid UTF-8 on its own.
+            progress_chan.send(Payload(percent_decode(chunk.as_bytes())));
+        }
+
+        start = end;
+        if start >= bytes.len() {
+            break;
+        }
+    }
+
+    progress_chan.send(Done(Ok(())));
+}
+
+/// Backs a non-final chunk boundary up so it never lands in the middle of a `%XX` escape or a
+/// multi-byte UTF-8 character, leaving the whole unit for the next chunk to decode. Literal
+/// (non-percent-escaped) multi-byte characters are a supported part of the data portion (see
+/// `plain_charset_literal_non_ascii`), so a fixed byte-offset boundary isn't safe to slice on
+/// without this check: `str::slice` panics unless both ends fall on a char boundary.
+fn trim_partial_unit(data: &str, start: uint, end: uint) -> uint {
+    let bytes = data.as_bytes();
+    if end >= bytes.len() {
+        return end;
+    }
+
+    let mut end = end;
+    if end > start && bytes[end - 1] == ('%' as u8) {
+        end -= 1;
+    } else if end > start + 1 && bytes[end - 2] == ('%' as u8) {
+        end -= 2;
+    }
+
+    while end > start && !data.is_char_boundary(end) {
+        end -= 1;
+    }
+    end
+}
+
+/// Percent-decodes `input` into the raw bytes it represents, without ever reinterpreting those
+/// bytes as a particular text encoding. A malformed `%` escape (not followed by two hex digits)
+/// is passed through unchanged.
+fn percent_decode(input: &[u8]) -> Vec<u8> {
+    let mut decoded = Vec::new();
+    let mut i = 0u;
+    while i < input.len() {
+        let byte = input[i];
+        if byte == '%' as u8 && i + 2 < input.len() {
+            let hi = (input[i + 1] as char).to_digit(16);
+            let lo = (input[i + 2] as char).to_digit(16);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => {
+                    decoded.push(((hi << 4) | lo) as u8);
+                    i += 3;
+                    continue;
+                }
+                _ => {}
             }
         }
-    } else {
-        // FIXME: Since the %-decoded URL is already a str, we can't
-        // handle UTF8-incompatible encodings.
-        let bytes: &[u8] = parts[1].as_bytes();
-        progress_chan.send(Payload(bytes.iter().map(|&x| x).collect()));
-        progress_chan.send(Done(Ok(())));
+        decoded.push(byte);
+        i += 1;
+    }
+    decoded
+}
+
+/// Parses the `type "/" subtype *(";" parameter)` grammar (RFC 2045) used for the content-type
+/// segment of a `data:` URI, returning the same `Option<(type, subtype)>` / charset shape that
+/// `Metadata` stores. This is hand-rolled rather than delegating to rust-http so that data: URIs
+/// no longer depend on it (see rust-http#25, which can send its parser into an infinite loop).
+/// The caller must already have stripped any trailing `;base64`, since RFC 2397 treats it as a
+/// special suffix rather than a `key=value` parameter.
+fn parse_media_type(input: &str) -> (Option<(~str, ~str)>, Option<~str>) {
+    let input = input.trim();
+    if input.is_empty() {
+        // RFC 2397: "If <mediatype> is omitted, it defaults to text/plain;charset=US-ASCII."
+        return (Some(("text".to_owned(), "plain".to_owned())), Some("US-ASCII".to_owned()));
     }
+
+    let mut segments = input.split(';');
+    let type_and_subtype = segments.next().unwrap();
+    let type_and_subtype: ~[&str] = type_and_subtype.splitn('/', 1).collect();
+    let content_type = match type_and_subtype.as_slice() {
+        [ty, subty] => Some((ty.trim().to_ascii_lower(), subty.trim().to_ascii_lower())),
+        _ => None,
+    };
+
+    let mut charset = None;
+    for param in segments {
+        let mut pieces = param.splitn('=', 1);
+        let key = match pieces.next() { Some(k) => k.trim(), None => continue };
+        let value = match pieces.next() { Some(v) => v.trim(), None => continue };
+        if key.eq_ignore_ascii_case("charset") {
+            charset = Some(unquote(value));
+        }
+    }
+
+    (content_type, charset)
+}
+
+/// Strips surrounding double quotes from a media-type parameter value, if present, and
+/// unescapes any `\x` quoted-pairs within it (RFC 2045 `quoted-string`). Unquoted `token`
+/// values are returned unchanged.
+fn unquote(value: &str) -> ~str {
+    if value.len() < 2 || !value.starts_with("\"") || !value.ends_with("\"") {
+        return value.to_owned();
+    }
+
+    let mut unescaped = StrBuf::new();
+    let mut chars = value.slice(1, value.len() - 1).chars();
+    loop {
+        match chars.next() {
+            None => break,
+            Some('\\') => match chars.next() {
+                Some(c) => unescaped.push_char(c),
+                None => break,
+            },
+            Some(c) => unescaped.push_char(c),
+        }
+    }
+    unescaped.into_owned()
 }
 
 #[cfg(test)]
@@ -76,7 +241,7 @@ fn assert_parse(url:          &'static str,
     use std::comm;
 
     let (start_chan, start_port) = comm::channel();
-    load(FromStr::from_str(url).unwrap(), start_chan);
+    load(FromStr::from_str(url).unwrap(), start_chan, Opts::default());
 
     let response = start_port.recv();
     assert_eq!(&response.metadata.content_type, &content_type);
@@ -102,7 +267,10 @@ fn empty_invalid() {
 
 #[test]
 fn plain() {
-    assert_parse("data:,hello%20world", None, None, Some(bytes!("hello world").iter().map(|&x| x).collect()));
+    // RFC 2397: an omitted media type defaults to text/plain;charset=US-ASCII.
+    assert_parse("data:,hello%20world",
+        Some(("text".to_owned(), "plain".to_owned())), Some("US-ASCII".to_owned()),
+        Some(bytes!("hello world").iter().map(|&x| x).collect()));
 }
 
 #[test]
@@ -117,9 +285,27 @@ fn plain_charset() {
         Some(("text".to_owned(), "plain".to_owned())), Some("latin1".to_owned()), Some(bytes!("hello").iter().map(|&x| x).collect()));
 }
 
+#[test]
+fn plain_charset_koi8r_escaped() {
+    assert_parse("data:text/plain;charset=koi8-r,%F0%F2%E5%F7%E5%E4%20%ED%E5%E4%F7%E5%E4",
+        Some(("text".to_owned(), "plain".to_owned())), Some("koi8-r".to_owned()),
+        Some(vec!(0xF0, 0xF2, 0xE5, 0xF7, 0xE5, 0xE4, 0x20, 0xED, 0xE5, 0xE4, 0xF7, 0xE5, 0xE4)));
+}
+
+#[test]
+fn plain_charset_literal_non_ascii() {
+    // A literal (non-percent-escaped) multi-byte sequence must survive untouched.
+    assert_parse("data:text/plain;charset=utf-8,héllo",
+        Some(("text".to_owned(), "plain".to_owned())), Some("utf-8".to_owned()),
+        Some(bytes!("héllo").iter().map(|&x| x).collect()));
+}
+
 #[test]
 fn base64() {
-    assert_parse("data:;base64,C62+7w==", None, None, Some(vec!(0x0B, 0xAD, 0xBE, 0xEF)));
+    // The stripped ";base64" suffix must not be mistaken for a missing media type's parameter.
+    assert_parse("data:;base64,C62+7w==",
+        Some(("text".to_owned(), "plain".to_owned())), Some("US-ASCII".to_owned()),
+        Some(vec!(0x0B, 0xAD, 0xBE, 0xEF)));
 }
 
 #[test]
@@ -128,9 +314,141 @@ fn base64_ct() {
         Some(("application".to_owned(), "octet-stream".to_owned())), None, Some(vec!(0x0B, 0xAD, 0xBE, 0xEF)));
 }
 
+#[test]
+fn plain_charset_quoted() {
+    assert_parse("data:text/plain;charset=\"latin1\",hello",
+        Some(("text".to_owned(), "plain".to_owned())), Some("latin1".to_owned()), Some(bytes!("hello").iter().map(|&x| x).collect()));
+}
+
+#[test]
+fn plain_charset_quoted_escaped() {
+    assert_parse("data:text/plain;charset=\"lat\\in1\",hello",
+        Some(("text".to_owned(), "plain".to_owned())), Some("latin1".to_owned()), Some(bytes!("hello").iter().map(|&x| x).collect()));
+}
+
 #[test]
 fn base64_charset() {
     assert_parse("data:text/plain;charset=koi8-r;base64,8PLl9+XkIO3l5Pfl5A==",
         Some(("text".to_owned(), "plain".to_owned())), Some("koi8-r".to_owned()),
         Some(vec!(0xF0, 0xF2, 0xE5, 0xF7, 0xE5, 0xE4, 0x20, 0xED, 0xE5, 0xE4, 0xF7, 0xE5, 0xE4)));
 }
+
+/// Drains every `Payload` from `response`, asserting there's a final `Done(Ok(()))`, and returns
+/// the concatenated bytes. Used by the tests below that force the chunked/spawned path via a
+/// tiny `data_uri_chunk_threshold`, where the body may arrive as more than one `Payload`.
+#[cfg(test)]
+fn collect_payloads(response: &LoadResponse) -> Vec<u8> {
+    let mut received = Vec::new();
+    loop {
+        match response.progress_port.recv() {
+            Payload(bytes) => received.push_all(bytes.as_slice()),
+            Done(Ok(())) => break,
+            Done(Err(e)) => fail!("load failed: {}", e),
+        }
+    }
+    received
+}
+
+#[test]
+fn chunked_plain_above_threshold() {
+    use std::from_str::FromStr;
+    use std::comm;
+
+    let mut opts = Opts::default();
+    opts.data_uri_chunk_threshold = 1;
+
+    let (start_chan, start_port) = comm::channel();
+    load(FromStr::from_str("data:text/plain,hello%20world").unwrap(), start_chan, opts);
+
+    let response = start_port.recv();
+    assert_eq!(&response.metadata.content_type, &Some(("text".to_owned(), "plain".to_owned())));
+    assert_eq!(collect_payloads(&response), bytes!("hello world").iter().map(|&x| x).collect());
+}
+
+#[test]
+fn chunked_base64_above_threshold() {
+    use std::from_str::FromStr;
+    use std::comm;
+
+    let mut opts = Opts::default();
+    opts.data_uri_chunk_threshold = 1;
+
+    let (start_chan, start_port) = comm::channel();
+    load(FromStr::from_str("data:;base64,C62+7w==").unwrap(), start_chan, opts);
+
+    let response = start_port.recv();
+    assert_eq!(collect_payloads(&response), vec!(0x0B, 0xAD, 0xBE, 0xEF));
+}
+
+/// Drains every `Payload` sent directly to `port` by `decode_in_chunks`, returning the
+/// concatenated bytes and the number of `Payload`s it took to deliver them.
+#[cfg(test)]
+fn collect_chunks(port: &Receiver<ProgressMsg>) -> (Vec<u8>, uint) {
+    let mut received = Vec::new();
+    let mut payloads = 0u;
+    loop {
+        match port.recv() {
+            Payload(bytes) => { received.push_all(bytes.as_slice()); payloads += 1; }
+            Done(Ok(())) => break,
+            Done(Err(e)) => fail!("decode failed: {}", e),
+        }
+    }
+    (received, payloads)
+}
+
+#[test]
+fn decode_in_chunks_splits_without_breaking_percent_escape() {
+    use std::comm;
+
+    // With chunk_size = 6, the first boundary lands at offset 6, inside the `%20` escape (which
+    // occupies offsets 5-7): bytes[5] == '%', so the back-off must kick in. A chunk_size of 4
+    // would look plausible too, but offsets 4/8/12 all fall outside the escape and never
+    // exercise the back-off at all.
+    let (chan, port) = comm::channel();
+    decode_in_chunks("hello%20world", false, 6, &chan);
+
+    let (received, payloads) = collect_chunks(&port);
+    assert!(payloads > 1);
+    assert_eq!(received, bytes!("hello world").iter().map(|&x| x).collect());
+}
+
+#[test]
+fn decode_in_chunks_splits_without_breaking_utf8_char() {
+    use std::comm;
+
+    // With chunk_size = 5, the first boundary lands at offset 5, inside "é"'s two UTF-8 bytes
+    // (which occupy offsets 4-5), so the is_char_boundary back-off must kick in.
+    let (chan, port) = comm::channel();
+    decode_in_chunks("aaaaébbbb", false, 5, &chan);
+
+    let (received, payloads) = collect_chunks(&port);
+    assert!(payloads > 1);
+    assert_eq!(received, bytes!("aaaaébbbb").iter().map(|&x| x).collect());
+}
+
+#[test]
+fn decode_in_chunks_splits_base64_on_group_boundaries() {
+    use std::comm;
+
+    let (chan, port) = comm::channel();
+    decode_in_chunks("C62+7w==", true, 4, &chan);
+
+    let (received, payloads) = collect_chunks(&port);
+    assert!(payloads > 1);
+    assert_eq!(received, vec!(0x0B, 0xAD, 0xBE, 0xEF));
+}
+
+#[test]
+fn malformed_base64_length_fails_atomically() {
+    use std::from_str::FromStr;
+    use std::comm;
+
+    // Below opts.data_uri_chunk_threshold, so this takes the single-shot path. Its length isn't
+    // a multiple of 4; the whole body must be validated as one group, so no Payload should ever
+    // be sent ahead of the error.
+    let (start_chan, start_port) = comm::channel();
+    load(FromStr::from_str("data:;base64,AAAAA").unwrap(), start_chan, Opts::default());
+
+    let response = start_port.recv();
+    assert_eq!(response.progress_port.recv(), Done(Err("non-base64 data uri".to_owned())));
+}